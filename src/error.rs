@@ -0,0 +1,45 @@
+use ash::{vk, LoadingError};
+use std::fmt;
+
+/// Error type returned by every command function, so `main` can always emit a JSON
+/// envelope instead of panicking with a backtrace callers can't parse.
+#[derive(Debug)]
+pub enum HelperError {
+    Loader(LoadingError),
+    Vulkan(vk::Result),
+    System(String),
+}
+
+impl HelperError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            HelperError::Loader(_) => "loader",
+            HelperError::Vulkan(_) => "vulkan",
+            HelperError::System(_) => "system",
+        }
+    }
+}
+
+impl fmt::Display for HelperError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HelperError::Loader(err) => write!(f, "{err}"),
+            HelperError::Vulkan(err) => write!(f, "{err}"),
+            HelperError::System(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for HelperError {}
+
+impl From<LoadingError> for HelperError {
+    fn from(err: LoadingError) -> Self {
+        HelperError::Loader(err)
+    }
+}
+
+impl From<vk::Result> for HelperError {
+    fn from(err: vk::Result) -> Self {
+        HelperError::Vulkan(err)
+    }
+}
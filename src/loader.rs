@@ -0,0 +1,56 @@
+use crate::error::HelperError;
+use ash::Entry;
+
+/// Indirection over how Vulkan entry points are obtained, so a caller-supplied library path
+/// can take the place of the default dynamic-linker search.
+pub trait VulkanLoader {
+    fn load(&self) -> Result<Entry, HelperError>;
+}
+
+/// Loads `libvulkan.so` / `vulkan-1.dll` from the default search path.
+pub struct DefaultLoader;
+
+impl VulkanLoader for DefaultLoader {
+    fn load(&self) -> Result<Entry, HelperError> {
+        Ok(unsafe { Entry::load() }?)
+    }
+}
+
+/// Loads an explicit loader library via `libloading`, falling back to the default search
+/// path if it can't be opened.
+pub struct PathLoader {
+    path: String,
+}
+
+impl PathLoader {
+    pub fn new(path: String) -> Self {
+        Self { path }
+    }
+}
+
+impl VulkanLoader for PathLoader {
+    fn load(&self) -> Result<Entry, HelperError> {
+        let path_err = match unsafe { Entry::load_from(&self.path) } {
+            Ok(entry) => return Ok(entry),
+            Err(err) => err,
+        };
+
+        // The default search path is only a fallback, not a substitute for reporting why the
+        // library the caller actually asked for couldn't be used.
+        match unsafe { Entry::load() } {
+            Ok(entry) => Ok(entry),
+            Err(default_err) => Err(HelperError::System(format!(
+                "failed to load Vulkan library from '{}': {path_err}; default loader search also failed: {default_err}",
+                self.path
+            ))),
+        }
+    }
+}
+
+/// Picks the loader to use for a run based on the optional `--vulkan-library` flag.
+pub fn resolve(vulkan_library: Option<String>) -> Box<dyn VulkanLoader> {
+    match vulkan_library {
+        Some(path) => Box::new(PathLoader::new(path)),
+        None => Box::new(DefaultLoader),
+    }
+}
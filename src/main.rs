@@ -1,11 +1,20 @@
-use ash::{vk, Entry};
+mod error;
+mod loader;
+
+use ash::extensions::ext::DebugUtils;
+use ash::vk;
 use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
 use libc::{c_void, dlopen, dlinfo, RTLD_NOW, RTLD_DI_LINKMAP, dlerror, dlclose};
+use error::HelperError;
+use loader::VulkanLoader;
 use std::ffi::{CString, CStr};
 use std::ptr::null_mut;
 use std::mem::transmute;
 use std::path::Path;
+use std::sync::Mutex;
+
+const VALIDATION_LAYER_NAME: &str = "VK_LAYER_KHRONOS_validation\0";
 
 const APP_NAME: &str = "Heroic\0";
 #[derive(Serialize, Deserialize)]
@@ -14,6 +23,113 @@ struct Device {
     pub major: u32,
     pub minor: u32,
     pub patch: u32,
+    pub device_type: String,
+    pub vendor_id: u32,
+    pub device_id: u32,
+    pub driver_version: String,
+    pub queue_families: Vec<QueueFamily>,
+    pub vram_bytes: u64,
+    pub score: u32,
+    pub suitable: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct QueueFamily {
+    pub graphics: bool,
+    pub compute: bool,
+    pub transfer: bool,
+    pub queue_count: u32,
+}
+
+fn device_type_to_str(device_type: vk::PhysicalDeviceType) -> String {
+    match device_type {
+        vk::PhysicalDeviceType::DISCRETE_GPU => "discrete",
+        vk::PhysicalDeviceType::INTEGRATED_GPU => "integrated",
+        vk::PhysicalDeviceType::VIRTUAL_GPU => "virtual",
+        vk::PhysicalDeviceType::CPU => "cpu",
+        _ => "other",
+    }
+    .to_string()
+}
+
+// Vendors pack `driverVersion` differently, so it can't be decoded with
+// `vk::api_version_*` except as a fallback for vendors we don't special-case.
+fn decode_driver_version(vendor_id: u32, v: u32) -> String {
+    match vendor_id {
+        0x10DE => format!(
+            "{}.{}.{}.{}",
+            (v >> 22) & 0x3ff,
+            (v >> 14) & 0xff,
+            (v >> 6) & 0xff,
+            v & 0x3f
+        ),
+        0x8086 if cfg!(windows) => format!("{}.{}", v >> 14, v & 0x3fff),
+        _ => format!(
+            "{}.{}.{}",
+            vk::api_version_major(v),
+            vk::api_version_minor(v),
+            vk::api_version_patch(v)
+        ),
+    }
+}
+
+fn get_queue_families(instance: &ash::Instance, device: vk::PhysicalDevice) -> Vec<QueueFamily> {
+    let properties = unsafe { instance.get_physical_device_queue_family_properties(device) };
+
+    properties
+        .iter()
+        .map(|family| QueueFamily {
+            graphics: family.queue_flags.contains(vk::QueueFlags::GRAPHICS),
+            compute: family.queue_flags.contains(vk::QueueFlags::COMPUTE),
+            transfer: family.queue_flags.contains(vk::QueueFlags::TRANSFER),
+            queue_count: family.queue_count,
+        })
+        .collect()
+}
+
+fn get_vram_bytes(instance: &ash::Instance, device: vk::PhysicalDevice) -> u64 {
+    let memory_properties = unsafe { instance.get_physical_device_memory_properties(device) };
+
+    memory_properties.memory_heaps[..memory_properties.memory_heap_count as usize]
+        .iter()
+        .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+        .map(|heap| heap.size)
+        .sum()
+}
+
+// `suitable` requires a graphics-capable queue family and all `require_extensions` present;
+// `score` then ranks suitable devices discrete > integrated > virtual > other.
+fn score_device(
+    instance: &ash::Instance,
+    device: vk::PhysicalDevice,
+    properties: &vk::PhysicalDeviceProperties,
+    queue_families: &[QueueFamily],
+    require_extensions: &[String],
+) -> (u32, bool) {
+    let has_graphics_queue = queue_families.iter().any(|family| family.graphics);
+
+    let available_extensions: Vec<String> = get_device_extensions(instance, device)
+        .into_iter()
+        .map(|extension| extension.name)
+        .collect();
+    let has_required_extensions = require_extensions
+        .iter()
+        .all(|required| available_extensions.contains(required));
+
+    let suitable = has_graphics_queue && has_required_extensions;
+
+    let score = if !suitable {
+        0
+    } else {
+        match properties.device_type {
+            vk::PhysicalDeviceType::DISCRETE_GPU => 1000,
+            vk::PhysicalDeviceType::INTEGRATED_GPU => 500,
+            vk::PhysicalDeviceType::VIRTUAL_GPU => 100,
+            _ => 1,
+        }
+    };
+
+    (score, suitable)
 }
 
 #[repr(C)]
@@ -25,21 +141,24 @@ struct LinkMap {
     l_prev: *mut LinkMap,
 }
 
-fn get_instance_version() -> [u32; 3] {
-    let entry = unsafe { Entry::load() }.expect("Failed to load vulkan");
+fn get_instance_version(loader: &dyn VulkanLoader) -> Result<[u32; 3], HelperError> {
+    let entry = loader.load()?;
     if let Ok(Some(version)) = entry.try_enumerate_instance_version() {
         let major = vk::api_version_major(version);
         let minor = vk::api_version_minor(version);
         let patch = vk::api_version_patch(version);
 
-        [major, minor, patch]
+        Ok([major, minor, patch])
     } else {
-        [0, 0, 0]
+        Ok([0, 0, 0])
     }
 }
 
-fn get_physical_versions() -> Vec<Device> {
-    let entry = unsafe { Entry::load() }.expect("Failed to load vulkan");
+fn get_physical_versions(
+    loader: &dyn VulkanLoader,
+    require_extensions: &[String],
+) -> Result<Vec<Device>, HelperError> {
+    let entry = loader.load()?;
 
     let app_info = vk::ApplicationInfo {
         p_application_name: APP_NAME.as_ptr() as *const i8,
@@ -53,40 +172,156 @@ fn get_physical_versions() -> Vec<Device> {
         ..Default::default()
     };
 
-    let instance = unsafe { entry.create_instance(&instance_info, None) }
-        .expect("Failed to create Vulkan instance");
+    let instance = unsafe { entry.create_instance(&instance_info, None) }?;
 
-    let devices =
-        unsafe { instance.enumerate_physical_devices() }.expect("Failed to enumerate devices");
+    let devices = unsafe { instance.enumerate_physical_devices() }?;
 
     let mut array: Vec<Device> = Vec::new();
     for device in devices {
         let properties = unsafe { instance.get_physical_device_properties(device) };
 
-        if properties.device_type == vk::PhysicalDeviceType::CPU {
-            continue;
-        }
-
-        let slice: &[u8; 256] = unsafe { std::mem::transmute(&properties.device_name) };
-        let name = String::from(std::str::from_utf8(slice).unwrap().trim_end_matches('\0'));
+        let name = cchar_array_to_string(&properties.device_name);
 
         let major = vk::api_version_major(properties.api_version);
         let minor = vk::api_version_minor(properties.api_version);
         let patch = vk::api_version_patch(properties.api_version);
+        let queue_families = get_queue_families(&instance, device);
+        let (score, suitable) = score_device(
+            &instance,
+            device,
+            &properties,
+            &queue_families,
+            require_extensions,
+        );
         let device_struct = Device {
             name,
             major,
             minor,
             patch,
+            device_type: device_type_to_str(properties.device_type),
+            vendor_id: properties.vendor_id,
+            device_id: properties.device_id,
+            driver_version: decode_driver_version(properties.vendor_id, properties.driver_version),
+            queue_families,
+            vram_bytes: get_vram_bytes(&instance, device),
+            score,
+            suitable,
         };
         array.push(device_struct);
     }
 
     unsafe { instance.destroy_instance(None) };
-    array
+    Ok(array)
+}
+
+#[derive(Serialize, Deserialize)]
+struct DebugMessage {
+    pub severity: String,
+    pub message_type: String,
+    pub message: String,
+}
+
+static DEBUG_MESSAGES: Mutex<Vec<DebugMessage>> = Mutex::new(Vec::new());
+
+fn severity_to_str(severity: vk::DebugUtilsMessageSeverityFlagsEXT) -> String {
+    match severity {
+        vk::DebugUtilsMessageSeverityFlagsEXT::ERROR => "error",
+        vk::DebugUtilsMessageSeverityFlagsEXT::WARNING => "warning",
+        vk::DebugUtilsMessageSeverityFlagsEXT::INFO => "info",
+        vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE => "verbose",
+        _ => "unknown",
+    }
+    .to_string()
 }
 
-fn get_dlerror<'a>() -> &'a str 
+fn message_type_to_str(message_type: vk::DebugUtilsMessageTypeFlagsEXT) -> String {
+    match message_type {
+        vk::DebugUtilsMessageTypeFlagsEXT::GENERAL => "general",
+        vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION => "validation",
+        vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE => "performance",
+        _ => "unknown",
+    }
+    .to_string()
+}
+
+unsafe extern "system" fn vulkan_debug_callback(
+    message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    message_type: vk::DebugUtilsMessageTypeFlagsEXT,
+    p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
+    _user_data: *mut c_void,
+) -> vk::Bool32 {
+    let message = CStr::from_ptr((*p_callback_data).p_message)
+        .to_string_lossy()
+        .into_owned();
+
+    DEBUG_MESSAGES.lock().unwrap().push(DebugMessage {
+        severity: severity_to_str(message_severity),
+        message_type: message_type_to_str(message_type),
+        message,
+    });
+
+    vk::FALSE
+}
+
+fn get_diagnostics(loader: &dyn VulkanLoader) -> Result<Vec<DebugMessage>, HelperError> {
+    let entry = loader.load()?;
+
+    let available_layers = entry.enumerate_instance_layer_properties().unwrap_or_default();
+    let validation_available = available_layers.iter().any(|layer| {
+        let name = unsafe { CStr::from_ptr(layer.layer_name.as_ptr()) };
+        name.to_bytes_with_nul() == VALIDATION_LAYER_NAME.as_bytes()
+    });
+
+    let mut layer_names: Vec<*const i8> = Vec::new();
+    if validation_available {
+        layer_names.push(VALIDATION_LAYER_NAME.as_ptr() as *const i8);
+    }
+
+    let extension_names = [DebugUtils::name().as_ptr()];
+
+    let app_info = vk::ApplicationInfo {
+        p_application_name: APP_NAME.as_ptr() as *const i8,
+        application_version: vk::make_api_version(0, 1, 0, 0),
+        api_version: vk::make_api_version(0, 1, 3, 0),
+        ..Default::default()
+    };
+
+    let instance_info = vk::InstanceCreateInfo {
+        p_application_info: &app_info,
+        enabled_layer_count: layer_names.len() as u32,
+        pp_enabled_layer_names: layer_names.as_ptr(),
+        enabled_extension_count: extension_names.len() as u32,
+        pp_enabled_extension_names: extension_names.as_ptr(),
+        ..Default::default()
+    };
+
+    let instance = unsafe { entry.create_instance(&instance_info, None) }?;
+
+    let debug_utils_loader = DebugUtils::new(&entry, &instance);
+
+    let messenger_info = vk::DebugUtilsMessengerCreateInfoEXT {
+        message_severity: vk::DebugUtilsMessageSeverityFlagsEXT::ERROR
+            | vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
+            | vk::DebugUtilsMessageSeverityFlagsEXT::INFO,
+        message_type: vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
+            | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION
+            | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE,
+        pfn_user_callback: Some(vulkan_debug_callback),
+        ..Default::default()
+    };
+
+    let messenger =
+        unsafe { debug_utils_loader.create_debug_utils_messenger(&messenger_info, None) }?;
+
+    unsafe {
+        debug_utils_loader.destroy_debug_utils_messenger(messenger, None);
+        instance.destroy_instance(None);
+    }
+
+    Ok(DEBUG_MESSAGES.lock().unwrap().drain(..).collect())
+}
+
+fn get_dlerror<'a>() -> &'a str
 {
     unsafe 
     {
@@ -99,58 +334,244 @@ fn get_dlerror<'a>() -> &'a str
     }
 }
 
-fn get_nvapi_path() -> String {
+fn get_nvapi_path() -> Result<String, HelperError> {
     let nvngx_lib = CString::new("libGLX_nvidia.so.0").expect("Failed to create CString");
     let nvngx = unsafe { dlopen(nvngx_lib.as_ptr(), RTLD_NOW) };
 
     if nvngx.is_null() {
-        panic!("dlopen failed: {}", get_dlerror());
+        return Err(HelperError::System(format!(
+            "dlopen failed: {}",
+            get_dlerror()
+        )));
     }
 
-
     let mut info: *mut LinkMap = null_mut();
     let ret = unsafe { dlinfo(nvngx, RTLD_DI_LINKMAP, transmute(&mut info)) };
 
     if ret != 0 {
-        panic!("dlinfo failed: {:?} {}", ret, get_dlerror());
+        return Err(HelperError::System(format!(
+            "dlinfo failed: {:?} {}",
+            ret,
+            get_dlerror()
+        )));
     }
 
-    let mut path = unsafe { Path::new(CStr::from_ptr((*info).l_name).to_str().expect("Failed to convert to str")) };
-    path = path.parent().expect("Failed to get parent path");
+    let l_name = match unsafe { CStr::from_ptr((*info).l_name) }.to_str() {
+        Ok(l_name) => l_name,
+        Err(err) => {
+            unsafe { dlclose(nvngx) };
+            return Err(HelperError::System(format!(
+                "driver reported a non-UTF8 library path: {err}"
+            )));
+        }
+    };
+
+    let path = match Path::new(l_name).parent() {
+        Some(path) => path,
+        None => {
+            unsafe { dlclose(nvngx) };
+            return Err(HelperError::System(format!(
+                "library path '{l_name}' has no parent directory"
+            )));
+        }
+    };
+
+    let result = path.display().to_string();
 
     unsafe { dlclose(nvngx) };
 
-    return path.display().to_string();
+    Ok(result)
+}
+
+fn cchar_array_to_string(chars: &[std::os::raw::c_char]) -> String {
+    unsafe { CStr::from_ptr(chars.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExtensionInfo {
+    pub name: String,
+    pub spec_version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LayerInfo {
+    pub name: String,
+    pub description: String,
+    pub spec_version: u32,
+    pub implementation_version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DeviceExtensions {
+    pub name: String,
+    pub extensions: Vec<ExtensionInfo>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExtensionsReport {
+    pub instance: Vec<ExtensionInfo>,
+    pub devices: Vec<DeviceExtensions>,
+}
+
+fn extension_property_to_info(extension: &vk::ExtensionProperties) -> ExtensionInfo {
+    ExtensionInfo {
+        name: cchar_array_to_string(&extension.extension_name),
+        spec_version: extension.spec_version,
+    }
+}
+
+fn get_device_extensions(instance: &ash::Instance, device: vk::PhysicalDevice) -> Vec<ExtensionInfo> {
+    let extensions = unsafe { instance.enumerate_device_extension_properties(device) }
+        .unwrap_or_default();
+
+    extensions.iter().map(extension_property_to_info).collect()
+}
+
+fn get_instance_extensions(loader: &dyn VulkanLoader) -> Result<Vec<ExtensionInfo>, HelperError> {
+    let entry = loader.load()?;
+    let extensions = entry.enumerate_instance_extension_properties(None)?;
+
+    Ok(extensions.iter().map(extension_property_to_info).collect())
+}
+
+fn get_instance_layers(loader: &dyn VulkanLoader) -> Result<Vec<LayerInfo>, HelperError> {
+    let entry = loader.load()?;
+    let layers = entry.enumerate_instance_layer_properties()?;
+
+    Ok(layers
+        .iter()
+        .map(|layer| LayerInfo {
+            name: cchar_array_to_string(&layer.layer_name),
+            description: cchar_array_to_string(&layer.description),
+            spec_version: layer.spec_version,
+            implementation_version: layer.implementation_version,
+        })
+        .collect())
+}
+
+fn get_extensions_report(loader: &dyn VulkanLoader) -> Result<ExtensionsReport, HelperError> {
+    let instance_extensions = get_instance_extensions(loader)?;
+
+    let entry = loader.load()?;
+
+    let app_info = vk::ApplicationInfo {
+        p_application_name: APP_NAME.as_ptr() as *const i8,
+        application_version: vk::make_api_version(0, 1, 0, 0),
+        api_version: vk::make_api_version(0, 1, 3, 0),
+        ..Default::default()
+    };
+
+    let instance_info = vk::InstanceCreateInfo {
+        p_application_info: &app_info,
+        ..Default::default()
+    };
+
+    let instance = unsafe { entry.create_instance(&instance_info, None) }?;
+
+    let devices = unsafe { instance.enumerate_physical_devices() }?;
+
+    let device_extensions = devices
+        .iter()
+        .map(|&device| {
+            let properties = unsafe { instance.get_physical_device_properties(device) };
+            DeviceExtensions {
+                name: cchar_array_to_string(&properties.device_name),
+                extensions: get_device_extensions(&instance, device),
+            }
+        })
+        .collect();
+
+    unsafe { instance.destroy_instance(None) };
+
+    Ok(ExtensionsReport {
+        instance: instance_extensions,
+        devices: device_extensions,
+    })
 }
 
 #[derive(Subcommand)]
 enum Commands {
     InstanceVersion,
-    PhysicalVersions,
+    PhysicalVersions {
+        /// Return only the single highest-scoring device (currently only "best"). That device is
+        /// returned even when no device is suitable.
+        #[arg(long)]
+        select: Option<String>,
+
+        /// Device extension that must be present for a device to be considered suitable.
+        /// May be passed multiple times.
+        #[arg(long = "require-extension")]
+        require_extensions: Vec<String>,
+    },
     NvapiPath,
+    Diagnostics,
+    Extensions,
+    Layers,
 }
 
 #[derive(Parser)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Path to an explicit `libvulkan.so` / `vulkan-1.dll` to load before the default
+    /// dynamic-linker search path (useful for Flatpak sandboxes or Wine/Proton prefixes).
+    /// If it cannot be loaded, the default search path is used instead.
+    #[arg(long, global = true)]
+    vulkan_library: Option<String>,
 }
 
-fn main() {
-    let cli = Cli::parse();
+fn run(cli: &Cli, loader: &dyn VulkanLoader) -> Result<serde_json::Value, HelperError> {
     let data = match &cli.command {
-        Commands::InstanceVersion => {
-            let version = get_instance_version();
-            serde_json::to_string(&version).expect("Failed to create json output")
+        Commands::InstanceVersion => serde_json::to_value(get_instance_version(loader)?),
+        Commands::PhysicalVersions {
+            select,
+            require_extensions,
+        } => {
+            let mut devices = get_physical_versions(loader, require_extensions)?;
+            match select.as_deref() {
+                None => {}
+                Some("best") => {
+                    devices.sort_by_key(|device| std::cmp::Reverse(device.score));
+                    devices.truncate(1);
+                }
+                Some(other) => {
+                    return Err(HelperError::System(format!(
+                        "unrecognized --select value '{other}' (expected \"best\")"
+                    )))
+                }
+            }
+            serde_json::to_value(devices)
         }
-        Commands::PhysicalVersions => {
-            let versions = get_physical_versions();
-            serde_json::to_string(&versions).expect("Failed to create json output")
+        Commands::NvapiPath => serde_json::to_value(get_nvapi_path()?),
+        Commands::Diagnostics => serde_json::to_value(get_diagnostics(loader)?),
+        Commands::Extensions => serde_json::to_value(get_extensions_report(loader)?),
+        Commands::Layers => serde_json::to_value(get_instance_layers(loader)?),
+    }
+    .expect("Failed to create json output");
+
+    Ok(data)
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let loader = loader::resolve(cli.vulkan_library.clone());
+
+    match run(&cli, loader.as_ref()) {
+        Ok(data) => {
+            print!("{}", serde_json::json!({ "ok": true, "data": data }));
         }
-        Commands::NvapiPath => {
-            let path = get_nvapi_path();
-            serde_json::to_string(&path).expect("Failed to create json output")
+        Err(err) => {
+            print!(
+                "{}",
+                serde_json::json!({
+                    "ok": false,
+                    "error": { "kind": err.kind(), "message": err.to_string() },
+                })
+            );
+            std::process::exit(1);
         }
-    };
-    print!("{}", data);
+    }
 }